@@ -3,13 +3,66 @@
 //! This code is intentially inefficient in some parts
 //! as it is intended as a task.
 
-use std::{hint::black_box, io::IoSlice, marker::PhantomData, thread, time::Duration};
+use std::{
+    hint::black_box, io::IoSlice, iter::Peekable, marker::PhantomData, thread, time::Duration,
+};
 
+use arrayvec::ArrayVec;
 use bytes::{Bytes, BytesMut};
 use rand::{distributions::Uniform, prelude::Distribution, seq::SliceRandom, thread_rng, Rng as _};
 
 const MAX_MSG_SIZE: usize = 1400;
 
+/// Upper bound on the number of iovecs grouped into a single message.
+///
+/// Most OS `sendmsg`/`writev` calls have an `IOV_MAX` limit anyway, so the
+/// message buffer is capped at this many segments regardless of how much
+/// room is left under [MAX_MSG_SIZE]. Keeping the buffer as a fixed-size
+/// `ArrayVec` means growing a message never triggers a heap allocation.
+const MAX_MSG_IOVECS: usize = 32;
+
+/// Number of complete messages accumulated before a `sendmmsg`-style batch is
+/// flushed in one syscall, amortizing its cost over many messages.
+const BATCH_SIZE: usize = 16;
+
+/// High-water mark for bytes that are queued but not yet handed to
+/// [send_batch]/[send_msg]. Once queuing the next payload would take the
+/// queued bytes past it, [Sender::send_payloads] backs off instead of draining
+/// the whole iterator unconditionally.
+const MAX_BUFFER_SIZE: usize = 32 * 1024;
+
+/// Default target capacity for the flat batch iovec store, in number of
+/// iovecs. Chosen to comfortably hold a full [BATCH_SIZE] of small messages
+/// without reallocating in the common case.
+const DEFAULT_BATCH_IOVEC_CAPACITY: usize = BATCH_SIZE * 4;
+
+/// Length and capacity of a `Sender`'s backing storage.
+///
+/// `capacity` is allowed to grow past the configured target capacity during a
+/// burst of large messages; [Sender::flush] shrinks it back down afterwards
+/// so the growth doesn't become a permanent memory cost.
+#[derive(Clone, Copy)]
+struct BufferLimits {
+    len: usize,
+    capacity: usize,
+}
+
+/// Outcome of a [Sender::send_payloads] call.
+///
+/// Every variant carries the number of messages sent on the wire during that
+/// call, which can be more than the number of payloads consumed if any
+/// payload had to be fragmented.
+enum SendStatus {
+    /// All payloads from the iterator were sent.
+    Sent(usize),
+    /// The in-flight byte budget was reached; some payloads are still
+    /// unconsumed in the iterator and the caller can resume later. Carries
+    /// the batch store's [BufferLimits] from just before the pending batch
+    /// was flushed, since by the time `send_payloads` returns the flush has
+    /// already emptied it.
+    Paused(usize, BufferLimits),
+}
+
 // We need to specify that the lifetime of the items in the buffer don't depend on the struct
 // `Sender` itself. We can achieve that by either setting that the struct outlive the data in the
 // buffer, or that the data outlive the struct itself.
@@ -17,7 +70,26 @@ const MAX_MSG_SIZE: usize = 1400;
 // Define that data in buffer will outlive the `Sender` struct.
 struct Sender<'a, 'b: 'a> {
     id: usize,
-    buffer: Vec<IoSlice<'b>>,
+    buffer: ArrayVec<IoSlice<'b>, MAX_MSG_IOVECS>,
+    /// Iovecs of every message queued in the current batch, flattened into one
+    /// backing store so queuing a message never allocates.
+    batch_iovecs: Vec<IoSlice<'b>>,
+    /// End offset into `batch_iovecs` of each queued message, i.e. the index
+    /// table that splits the flat store back into per-message slices.
+    batch_bounds: ArrayVec<usize, BATCH_SIZE>,
+    /// Bytes queued into `buffer`/`batch_iovecs` but not yet handed to
+    /// [send_batch]/[send_msg]. Bounded by [MAX_BUFFER_SIZE].
+    in_flight_bytes: usize,
+    /// Byte offset already sent for an oversized payload whose fragmentation
+    /// was paused partway through by the in-flight budget; `0` when no
+    /// fragmentation is in progress. Lets a payload larger than
+    /// [MAX_BUFFER_SIZE] still be delivered in full, one budget-sized slice
+    /// of fragments at a time, instead of being dropped.
+    fragment_offset: usize,
+    /// Target capacity for `batch_iovecs`. Stays fixed unless changed via
+    /// [Sender::set_target_capacity]; `batch_iovecs`'s actual capacity is
+    /// shrunk back toward it once it overshoots by more than 2x.
+    target_capacity: usize,
     phantom: PhantomData<&'a str>,
 }
 
@@ -33,30 +105,198 @@ impl<'a, 'b: 'a> Sender<'a, 'b> {
     /// Send payloads.
     ///
     /// We want to send payloads grouped together to messages.
-    /// The total size of a message must not be larger than [MAX_MSG_SIZE].
-    /// To avoid extra allocations, we are creating `IoSlice` of buffers.
+    /// The total size of a message must not be larger than [MAX_MSG_SIZE], and a
+    /// message must not hold more than [MAX_MSG_IOVECS] iovecs.
+    /// To avoid extra allocations, we are creating `IoSlice` of buffers in a
+    /// fixed-capacity `ArrayVec`, so growing a message never reallocates.
     /// The number and size of payloads passed as an iterator is random,
     /// so we have to dynamically "grow" a message until it cannot grow further.
-    fn send_payloads(&mut self, payloads: impl Iterator<Item = &'b Bytes>) {
+    ///
+    /// A payload that is itself `>= MAX_MSG_SIZE` cannot be grouped with anything
+    /// else, so it is fragmented into `ceil(len / MAX_MSG_SIZE)` messages, each a
+    /// zero-copy `Bytes::slice` of the original payload, sent out-of-band via
+    /// [send_msg] instead of going through the batch. Any messages already
+    /// queued from earlier, smaller payloads are flushed first, so the
+    /// fragments never jump ahead of payloads that arrived earlier in the
+    /// iterator.
+    ///
+    /// Grouped messages are not sent right away: they are queued into a batch
+    /// of up to [BATCH_SIZE] messages and handed to [send_batch] in one go,
+    /// amortizing the syscall cost over many messages. Any partial batch left
+    /// once `payloads` is exhausted is flushed before returning.
+    ///
+    /// Bytes queued but not yet flushed count against [MAX_BUFFER_SIZE]. Once
+    /// that budget would be exceeded, sending backs off with
+    /// [SendStatus::Paused] instead of draining the whole iterator
+    /// unconditionally, leaving the remaining payloads unconsumed in
+    /// `payloads` so the caller can resume once earlier messages have gone
+    /// out. A payload larger than [MAX_BUFFER_SIZE] is fragmented the same
+    /// way as any other oversized payload; it just takes more than one
+    /// [send_fragmented] call (and so possibly more than one `send_payloads`
+    /// call) to fully drain, instead of ever being dropped. Every
+    /// [SendStatus] variant carries the number of messages sent on the wire
+    /// during the call.
+    fn send_payloads<I>(&mut self, payloads: &mut Peekable<I>) -> SendStatus
+    where
+        I: Iterator<Item = &'b Bytes>,
+    {
         self.buffer.clear();
+        let mut num_messages_sent = 0;
+
+        while let Some(&next_payload) = payloads.peek() {
+            let payload_len = next_payload.len();
+
+            if payload_len >= MAX_MSG_SIZE {
+                // Flush whatever is already queued first, so messages that
+                // arrived earlier in the iterator don't get reordered behind
+                // this payload's out-of-band fragments.
+                let limits = self.limits();
+                num_messages_sent += self.flush();
 
-        let mut payloads = payloads.peekable();
+                let (fragments_sent, done) = self.send_fragmented(next_payload);
+                num_messages_sent += fragments_sent;
+
+                if !done {
+                    return SendStatus::Paused(num_messages_sent, limits);
+                }
+
+                payloads.next();
+                continue;
+            }
+
+            if self.in_flight_bytes + payload_len > MAX_BUFFER_SIZE {
+                let limits = self.limits();
+                num_messages_sent += self.flush();
+                return SendStatus::Paused(num_messages_sent, limits);
+            }
 
-        while payloads.peek().is_some() {
             self.buffer.clear();
             let mut msg_size = 0;
 
             'msg_growing: while let Some(next_payload) = payloads.peek() {
-                if msg_size + next_payload.len() < MAX_MSG_SIZE {
-                    msg_size += next_payload.len();
+                let next_len = next_payload.len();
+
+                if next_len >= MAX_MSG_SIZE
+                    || self.buffer.len() >= MAX_MSG_IOVECS
+                    || self.in_flight_bytes + msg_size + next_len > MAX_BUFFER_SIZE
+                {
+                    break 'msg_growing;
+                }
+
+                if msg_size + next_len < MAX_MSG_SIZE {
+                    msg_size += next_len;
                     self.buffer.push(IoSlice::new(payloads.next().unwrap()));
                 } else {
                     break 'msg_growing;
                 }
             }
 
-            black_box(send_msg(self.buffer.as_slice()));
+            self.in_flight_bytes += msg_size;
+            num_messages_sent += self.queue_message();
         }
+
+        num_messages_sent += self.flush();
+        SendStatus::Sent(num_messages_sent)
+    }
+
+    /// Move the iovecs accumulated for the current message into the flat batch
+    /// store and record its boundary, flushing the batch if it is now full.
+    ///
+    /// Returns the number of messages sent, i.e. `BATCH_SIZE` if this queuing
+    /// triggered a flush, `0` otherwise.
+    fn queue_message(&mut self) -> usize {
+        self.batch_iovecs.extend(self.buffer.drain(..));
+        self.batch_bounds.push(self.batch_iovecs.len());
+
+        if self.batch_bounds.is_full() {
+            self.flush()
+        } else {
+            0
+        }
+    }
+
+    /// Force-send whatever messages are currently queued in the batch, even if
+    /// it is not full, and free their bytes from the in-flight budget.
+    ///
+    /// Returns the number of messages sent.
+    fn flush(&mut self) -> usize {
+        if self.batch_bounds.is_empty() {
+            return 0;
+        }
+
+        let mut messages: ArrayVec<&[IoSlice<'b>], BATCH_SIZE> = ArrayVec::new();
+        let mut start = 0;
+        for &end in &self.batch_bounds {
+            messages.push(&self.batch_iovecs[start..end]);
+            start = end;
+        }
+
+        black_box(send_batch(&messages));
+        let num_messages_sent = messages.len();
+        drop(messages);
+
+        let flushed_bytes: usize = self.batch_iovecs.iter().map(|iov| iov.len()).sum();
+        self.in_flight_bytes = self.in_flight_bytes.saturating_sub(flushed_bytes);
+        self.batch_iovecs.clear();
+        self.batch_bounds.clear();
+
+        // A burst of large messages can leave `batch_iovecs` holding onto a lot
+        // more capacity than it needs steady-state; shrink it back toward the
+        // target once it has grown past double that target.
+        if self.limits().capacity > self.target_capacity.saturating_mul(2) {
+            self.batch_iovecs.shrink_to(self.target_capacity);
+        }
+
+        num_messages_sent
+    }
+
+    /// Current length and capacity of the batch iovec store.
+    fn limits(&self) -> BufferLimits {
+        BufferLimits {
+            len: self.batch_iovecs.len(),
+            capacity: self.batch_iovecs.capacity(),
+        }
+    }
+
+    /// Change the target capacity the batch iovec store is shrunk toward
+    /// after a burst of large messages.
+    fn set_target_capacity(&mut self, target_capacity: usize) {
+        self.target_capacity = target_capacity;
+    }
+
+    /// Split a payload into `MAX_MSG_SIZE` chunks and send each one as its
+    /// own message, without copying the underlying bytes, stopping before the
+    /// next fragment would take the in-flight bytes plus the bytes sent by
+    /// this call past [MAX_BUFFER_SIZE].
+    ///
+    /// Resumes from `self.fragment_offset` if an earlier call paused partway
+    /// through this same payload, and saves it again if this call pauses
+    /// before reaching the end. Returns the number of fragments sent and
+    /// whether the payload was fully sent.
+    fn send_fragmented(&mut self, payload: &'b Bytes) -> (usize, bool) {
+        let mut num_fragments = 0;
+        let mut offset = self.fragment_offset;
+        let mut bytes_sent = 0;
+
+        while offset < payload.len() {
+            let end = (offset + MAX_MSG_SIZE).min(payload.len());
+            let fragment_len = end - offset;
+
+            if self.in_flight_bytes + bytes_sent + fragment_len > MAX_BUFFER_SIZE {
+                self.fragment_offset = offset;
+                return (num_fragments, false);
+            }
+
+            let fragment = payload.slice(offset..end);
+            black_box(send_msg(&[IoSlice::new(&fragment)]));
+
+            bytes_sent += fragment_len;
+            num_fragments += 1;
+            offset = end;
+        }
+
+        self.fragment_offset = 0;
+        (num_fragments, true)
     }
 }
 
@@ -71,7 +311,12 @@ fn main() {
 
     let mut sender = Sender {
         id: 1,
-        buffer: Vec::new(),
+        buffer: ArrayVec::new(),
+        batch_iovecs: Vec::with_capacity(DEFAULT_BATCH_IOVEC_CAPACITY),
+        batch_bounds: ArrayVec::new(),
+        in_flight_bytes: 0,
+        fragment_offset: 0,
+        target_capacity: DEFAULT_BATCH_IOVEC_CAPACITY,
         phantom: PhantomData,
     };
 
@@ -79,7 +324,31 @@ fn main() {
         // Choose a random set of payloads to pass to `send_payloads`.
         let num_payloads = num_payloads_sampler.sample(&mut thread_rng());
         let random_payloads = payloads.choose_multiple(&mut thread_rng(), num_payloads);
-        sender.send_payloads(random_payloads);
+        let mut pending = random_payloads.peekable();
+
+        // Keep calling `send_payloads` until this batch of payloads is fully
+        // sent, backing off when the sender asks for it instead of forcing
+        // the whole iterator through unconditionally.
+        loop {
+            match sender.send_payloads(&mut pending) {
+                SendStatus::Sent(num_messages_sent) => {
+                    black_box(num_messages_sent);
+                    break;
+                }
+                SendStatus::Paused(num_messages_sent, limits) => {
+                    black_box(num_messages_sent);
+
+                    // Sustained backpressure means the default target is too
+                    // tight for the current load; let the batch store grow a
+                    // bit more before it gets clamped back down.
+                    if limits.len > 0 {
+                        sender.set_target_capacity(limits.capacity.max(limits.len));
+                    }
+
+                    thread::sleep(Duration::from_nanos(100));
+                }
+            }
+        }
 
         // Sleep to throttle the binary a bit.
         thread::sleep(Duration::from_nanos(100));
@@ -95,6 +364,13 @@ fn send_msg(iovs: &[IoSlice]) {
     });
 }
 
+/// Send a batch of messages in one syscall (`sendmmsg`-style).
+fn send_batch(msgs: &[&[IoSlice]]) {
+    black_box({
+        let _ = msgs;
+    });
+}
+
 /// Create a random payload.
 fn random_payload(min_size: usize, max_size: usize) -> Bytes {
     let cap = Uniform::new_inclusive(min_size, max_size).sample(&mut thread_rng());
@@ -102,3 +378,128 @@ fn random_payload(min_size: usize, max_size: usize) -> Bytes {
     thread_rng().fill(&mut buf[..]);
     buf.freeze()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sender<'a, 'b>() -> Sender<'a, 'b> {
+        Sender {
+            id: 0,
+            buffer: ArrayVec::new(),
+            batch_iovecs: Vec::with_capacity(DEFAULT_BATCH_IOVEC_CAPACITY),
+            batch_bounds: ArrayVec::new(),
+            in_flight_bytes: 0,
+            fragment_offset: 0,
+            target_capacity: DEFAULT_BATCH_IOVEC_CAPACITY,
+            phantom: PhantomData,
+        }
+    }
+
+    #[test]
+    fn fragments_oversized_payload_into_multiple_messages() {
+        let payload = Bytes::from(vec![0u8; 2 * MAX_MSG_SIZE + 1]);
+        let payloads = [payload];
+        let mut pending = payloads.iter().peekable();
+
+        let status = sender().send_payloads(&mut pending);
+
+        assert!(matches!(status, SendStatus::Sent(3)));
+        assert!(pending.peek().is_none());
+    }
+
+    #[test]
+    fn flushes_pending_batch_before_fragmenting_an_oversized_payload() {
+        // Build up a backlog of queued, unflushed messages close to the
+        // in-flight budget, then append an oversized payload whose fragments
+        // only fit if that backlog is flushed (and its bytes freed from the
+        // budget) before fragmenting starts. If the batch were bypassed
+        // instead of flushed first, its bytes would still count against the
+        // budget and the oversized payload would pause partway through
+        // instead of fully draining here.
+        let small = Bytes::from(vec![0u8; MAX_MSG_SIZE - 1]);
+        let mut payloads: Vec<Bytes> = std::iter::repeat_n(small, 15).collect();
+        payloads.push(Bytes::from(vec![0u8; 10 * MAX_MSG_SIZE]));
+        let mut pending = payloads.iter().peekable();
+
+        let status = sender().send_payloads(&mut pending);
+
+        // 15 messages for the backlog, flushed first, plus 10 fragments for
+        // the oversized payload.
+        assert!(matches!(status, SendStatus::Sent(25)));
+        assert!(pending.peek().is_none());
+    }
+
+    #[test]
+    fn paused_carries_the_batch_limits_from_just_before_the_flush() {
+        // Queue a backlog, then an oversized payload too big to fully
+        // fragment under the freed-up budget, so the call pauses. The
+        // `BufferLimits` on `Paused` must reflect the batch as it was just
+        // before it got flushed to make room for fragmenting, not the
+        // already-empty state `flush` leaves it in.
+        let small = Bytes::from(vec![0u8; MAX_MSG_SIZE - 1]);
+        let mut payloads: Vec<Bytes> = std::iter::repeat_n(small, 15).collect();
+        payloads.push(Bytes::from(vec![0u8; 40 * MAX_MSG_SIZE]));
+        let mut pending = payloads.iter().peekable();
+
+        let status = sender().send_payloads(&mut pending);
+
+        let SendStatus::Paused(_, limits) = status else {
+            panic!("expected Paused, got a different status");
+        };
+        assert_eq!(limits.len, 15);
+    }
+
+    #[test]
+    fn fragments_a_payload_larger_than_max_buffer_size_across_multiple_calls() {
+        // A single payload bigger than the whole in-flight budget is not
+        // dropped; it is fragmented across as many `send_payloads` calls as
+        // the budget requires, without losing any of it.
+        let total_len = MAX_BUFFER_SIZE + 5000;
+        let payload = Bytes::from(vec![0u8; total_len]);
+        let payloads = [payload];
+        let mut pending = payloads.iter().peekable();
+
+        let mut sender = sender();
+        let mut total_messages_sent = 0;
+        let mut calls = 0;
+
+        loop {
+            calls += 1;
+            match sender.send_payloads(&mut pending) {
+                SendStatus::Sent(num_messages_sent) => {
+                    total_messages_sent += num_messages_sent;
+                    break;
+                }
+                SendStatus::Paused(num_messages_sent, _limits) => {
+                    total_messages_sent += num_messages_sent;
+                }
+            }
+        }
+
+        assert!(
+            calls > 1,
+            "expected the oversized payload to span multiple calls"
+        );
+        assert_eq!(total_messages_sent, total_len.div_ceil(MAX_MSG_SIZE));
+        assert!(pending.peek().is_none());
+    }
+
+    #[test]
+    fn shrinks_batch_capacity_back_toward_target_after_a_burst() {
+        // Many small payloads grouped into several messages spill the flat
+        // batch store far past the tiny target capacity before the trailing
+        // flush of this call.
+        let payload = Bytes::from(vec![0u8; 50]);
+        let payloads: Vec<Bytes> = std::iter::repeat_n(payload, 200).collect();
+        let mut pending = payloads.iter().peekable();
+
+        let mut sender = sender();
+        sender.set_target_capacity(4);
+
+        let status = sender.send_payloads(&mut pending);
+
+        assert!(matches!(status, SendStatus::Sent(_)));
+        assert!(sender.limits().capacity <= 4 * 2);
+    }
+}